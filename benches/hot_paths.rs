@@ -0,0 +1,67 @@
+//benchmarks for the per-request hot paths, none of them touch the database
+use criterion::{ black_box, criterion_group, criterion_main, Criterion };
+use rust_app::{ Car, check_framing, get_query_param, is_request_complete, match_route };
+
+const POST_REQUEST: &[u8] =
+    b"POST /cars HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 59\r\n\r\n{\"brand\":\"Slow\",\"model\":\"Bytes\",\"year\":2015,\"price\":1234.5}";
+
+const CAR_JSON: &str = r#"{"id":7,"brand":"Toyota","model":"Corolla","year":2015,"price":12345.5}"#;
+
+//one request per route, in the order match_route tries them, plus one that matches nothing
+const ROUTED_REQUESTS: [(&str, Option<&str>); 8] = [
+    ("OPTIONS /cars HTTP/1.1\r\n\r\n", Some("OPTIONS")),
+    ("POST /cars HTTP/1.1\r\n\r\n", Some("POST /cars")),
+    ("GET /cars/7/exists HTTP/1.1\r\n\r\n", Some("GET /cars/{id}/exists")),
+    ("GET /cars/top?by=year HTTP/1.1\r\n\r\n", Some("GET /cars/top")),
+    ("GET /cars/7 HTTP/1.1\r\n\r\n", Some("GET /cars/{id}")),
+    ("GET /cars HTTP/1.1\r\n\r\n", Some("GET /cars")),
+    ("DELETE /cars/7 HTTP/1.1\r\n\r\n", Some("DELETE /cars/{id}")),
+    ("HEAD /cars HTTP/1.1\r\n\r\n", None),
+];
+
+const QUERY_REQUEST: &str = "GET /cars/top?by=price&order=desc&brand=Alfa+Romeo&model=Giulia%20Quadrifoglio HTTP/1.1\r\n\r\n";
+
+fn parsing(c: &mut Criterion) {
+    //baseline: the request is complete and well framed, so both checks run to the end
+    assert!(is_request_complete(POST_REQUEST));
+    assert_eq!(check_framing(POST_REQUEST), Ok(()));
+
+    c.bench_function("is_request_complete", |b| b.iter(|| is_request_complete(black_box(POST_REQUEST))));
+    c.bench_function("is_request_complete partial", |b| {
+        b.iter(|| is_request_complete(black_box(&POST_REQUEST[..POST_REQUEST.len() - 1])))
+    });
+    c.bench_function("check_framing", |b| b.iter(|| check_framing(black_box(POST_REQUEST))));
+}
+
+fn routing(c: &mut Criterion) {
+    for (request, route) in ROUTED_REQUESTS {
+        assert_eq!(match_route(request), route, "{}", request);
+    }
+
+    c.bench_function("match_route", |b| {
+        b.iter(|| {
+            for (request, _) in ROUTED_REQUESTS {
+                black_box(match_route(black_box(request)));
+            }
+        })
+    });
+}
+
+fn serialization(c: &mut Criterion) {
+    let car: Car = serde_json::from_str(CAR_JSON).unwrap();
+    assert_eq!(serde_json::to_string(&car).unwrap(), CAR_JSON);
+
+    c.bench_function("car deserialize", |b| b.iter(|| serde_json::from_str::<Car>(black_box(CAR_JSON)).unwrap()));
+    c.bench_function("car serialize", |b| b.iter(|| serde_json::to_string(black_box(&car)).unwrap()));
+}
+
+fn query_string(c: &mut Criterion) {
+    assert_eq!(get_query_param(QUERY_REQUEST, "brand").as_deref(), Some("Alfa Romeo"));
+    assert_eq!(get_query_param(QUERY_REQUEST, "model").as_deref(), Some("Giulia Quadrifoglio"));
+
+    c.bench_function("get_query_param", |b| b.iter(|| get_query_param(black_box(QUERY_REQUEST), "model")));
+    c.bench_function("get_query_param missing", |b| b.iter(|| get_query_param(black_box(QUERY_REQUEST), "year")));
+}
+
+criterion_group!(benches, parsing, routing, serialization, query_string);
+criterion_main!(benches);
@@ -0,0 +1,267 @@
+//request parsing, routing and the Car model; no database, so they can be benchmarked on their own
+use std::borrow::Cow;
+
+#[macro_use]
+extern crate serde_derive;
+
+//Model: Car struct with id, brand, model, year, price and the read-only computed age
+#[derive(Serialize, Deserialize)]
+pub struct Car {
+    pub id: Option<i32>,
+    pub brand: String,
+    pub model: String,
+    pub year: i32,
+    pub price: f64,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub age: Option<i32>,
+}
+
+//match request to its route template
+pub fn match_route(request: &str) -> Option<&'static str> {
+    match request {
+        r if r.starts_with("OPTIONS ") => Some("OPTIONS"),
+        r if r.starts_with("POST /cars") => Some("POST /cars"),
+        r if r.starts_with("GET /cars/") && get_route_path(get_path(r)) == "/cars/{id}/exists" =>
+            Some("GET /cars/{id}/exists"),
+        r if r.starts_with("GET /cars/facets") => Some("GET /cars/facets"),
+        r if r.starts_with("GET /cars/price-distribution") => Some("GET /cars/price-distribution"),
+        r if r.starts_with("GET /cars/top") => Some("GET /cars/top"),
+        r if r.starts_with("GET /cars/") => Some("GET /cars/{id}"),
+        r if r.starts_with("GET /cars") => Some("GET /cars"),
+        r if r.starts_with("GET /admin/selftest") => Some("GET /admin/selftest"),
+        r if r.starts_with("PATCH /cars") && !r.starts_with("PATCH /cars/") => Some("PATCH /cars"),
+        r if r.starts_with("PUT /cars/") => Some("PUT /cars/{id}"),
+        r if r.starts_with("DELETE /cars/") => Some("DELETE /cars/{id}"),
+        _ => None,
+    }
+}
+
+//route template of a path, numeric segments become {id}
+pub fn get_route_path(path: &str) -> String {
+    path.split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .map(|segment| if segment.parse::<i32>().is_ok() { "{id}" } else { segment })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+//whether the headers and as much body as they declare have been read
+pub fn is_request_complete(request: &[u8]) -> bool {
+    if !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        return false;
+    }
+
+    let (headers, body) = split_head(request);
+
+    //without a usable Content-Length there is no body to wait for, check_framing rejects
+    //Transfer-Encoding and bad lengths
+    match get_header(&headers, "Content-Length").map(|length| length.parse::<usize>()) {
+        Some(Ok(length)) => body.len() >= length,
+        _ => true,
+    }
+}
+
+//reject requests whose body length is ambiguous, so they can't smuggle a second request;
+//chunked bodies aren't decoded, so any Transfer-Encoding is refused
+pub fn check_framing(request: &[u8]) -> Result<(), &'static str> {
+    let (headers, body) = split_head(request);
+    let content_lengths = get_headers(&headers, "Content-Length");
+    let transfer_encoding = get_header(&headers, "Transfer-Encoding").is_some();
+
+    match content_lengths.as_slice() {
+        [_, ..] if transfer_encoding => Err("Both Content-Length and Transfer-Encoding"),
+        _ if transfer_encoding => Err("Transfer-Encoding not supported"),
+        [] if body.is_empty() => Ok(()),
+        [] => Err("Body without Content-Length"),
+        [length] => {
+            //only plain digits, no sign or whitespace
+            if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err("Invalid Content-Length");
+            }
+
+            //compared on raw bytes, the body may not be valid UTF-8
+            match length.parse::<usize>() {
+                Ok(length) if body.len() > length => Err("Unexpected bytes after body"),
+                Ok(length) if body.len() < length => Err("Body shorter than Content-Length"),
+                Ok(_) => Ok(()),
+                Err(_) => Err("Invalid Content-Length"),
+            }
+        }
+        _ => Err("Multiple Content-Length headers"),
+    }
+}
+
+//split raw request into its header block and body bytes
+pub fn split_head(request: &[u8]) -> (Cow<'_, str>, &[u8]) {
+    match request.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(position) => (String::from_utf8_lossy(&request[..position + 4]), &request[position + 4..]),
+        None => (String::from_utf8_lossy(request), &[]),
+    }
+}
+
+//Get path from request line
+pub fn get_path(request: &str) -> &str {
+    request.split_whitespace().nth(1).unwrap_or_default()
+}
+
+//Get all values of a header from request, header names are case insensitive
+pub fn get_headers<'a>(request: &'a str, name: &str) -> Vec<&'a str> {
+    request
+        .split("\r\n\r\n")
+        .next()
+        .unwrap_or_default()
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+        .collect()
+}
+
+//Get first value of a header from request
+pub fn get_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    get_headers(request, name).into_iter().next()
+}
+
+//Get query string parameter from request URL, percent-decoded
+pub fn get_query_param(request: &str, name: &str) -> Option<String> {
+    get_path(request)
+        .split_once('?')?
+        .1.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| decode_query_value(value))
+}
+
+//decode %XX escapes and '+' in a query string value
+pub fn decode_query_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing_accepts_matching_content_length() {
+        assert_eq!(check_framing(b"POST /cars HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}"), Ok(()));
+        assert_eq!(check_framing(b"GET /cars HTTP/1.1\r\nHost: x\r\n\r\n"), Ok(()));
+    }
+
+    #[test]
+    fn framing_rejects_content_length_with_transfer_encoding() {
+        let request = b"POST /cars HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n{}";
+
+        assert_eq!(check_framing(request), Err("Both Content-Length and Transfer-Encoding"));
+    }
+
+    #[test]
+    fn framing_rejects_duplicate_content_length() {
+        let request = b"POST /cars HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\n{}";
+
+        assert_eq!(check_framing(request), Err("Multiple Content-Length headers"));
+    }
+
+    #[test]
+    fn framing_rejects_malformed_content_length() {
+        for length in ["-5", "+5", "abc", ""] {
+            let request = format!("POST /cars HTTP/1.1\r\nContent-Length: {}\r\n\r\n{{}}", length);
+
+            assert_eq!(check_framing(request.as_bytes()), Err("Invalid Content-Length"), "{}", length);
+        }
+    }
+
+    #[test]
+    fn framing_rejects_trailing_bytes() {
+        let request = b"POST /cars HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}GET /x HTTP/1.1\r\n\r\n";
+
+        assert_eq!(check_framing(request), Err("Unexpected bytes after body"));
+    }
+
+    #[test]
+    fn framing_counts_raw_bytes_of_non_utf8_body() {
+        let mut request = b"POST /cars HTTP/1.1\r\nContent-Length: 2\r\n\r\n".to_vec();
+        request.extend_from_slice(&[0xff, 0xfe]);
+
+        assert_eq!(check_framing(&request), Ok(()));
+    }
+
+    #[test]
+    fn framing_rejects_transfer_encoding() {
+        let chunked = b"POST /cars HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n";
+        let smuggled = b"GET /cars HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /x HTTP/1.1\r\n\r\n0\r\n\r\n";
+        let gzip = b"POST /cars HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n{}";
+
+        assert_eq!(check_framing(chunked), Err("Transfer-Encoding not supported"));
+        assert_eq!(check_framing(smuggled), Err("Transfer-Encoding not supported"));
+        assert_eq!(check_framing(gzip), Err("Transfer-Encoding not supported"));
+    }
+
+    #[test]
+    fn framing_rejects_unframed_body() {
+        assert_eq!(check_framing(b"POST /cars HTTP/1.1\r\n\r\n{}"), Err("Body without Content-Length"));
+    }
+
+    #[test]
+    fn route_path_replaces_ids() {
+        assert_eq!(get_route_path("/cars"), "/cars");
+        assert_eq!(get_route_path("/cars/42"), "/cars/{id}");
+        assert_eq!(get_route_path("/cars/42/exists?x=1"), "/cars/{id}/exists");
+        assert_eq!(get_route_path("/cars/top"), "/cars/top");
+    }
+
+    #[test]
+    fn request_is_complete_only_once_fully_read() {
+        let request = b"POST /cars HTTP/1.1\r\nContent-Length: 59\r\n\r\n{\"brand\":\"Slow\",\"model\":\"Bytes\",\"year\":2015,\"price\":1234.5}";
+
+        for end in 0..request.len() {
+            assert!(!is_request_complete(&request[..end]), "complete after {} bytes", end);
+        }
+
+        assert!(is_request_complete(request));
+    }
+
+    #[test]
+    fn routes_match_their_templates() {
+        assert_eq!(match_route("GET /cars HTTP/1.1\r\n\r\n"), Some("GET /cars"));
+        assert_eq!(match_route("GET /cars/7 HTTP/1.1\r\n\r\n"), Some("GET /cars/{id}"));
+        assert_eq!(match_route("GET /cars/7/exists?x=1 HTTP/1.1\r\n\r\n"), Some("GET /cars/{id}/exists"));
+        assert_eq!(match_route("PATCH /cars/7 HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(match_route("HEAD /cars HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn query_params_are_percent_decoded() {
+        let request = "GET /cars?brand=Alfa+Romeo&model=C%2FX&bad=%zz HTTP/1.1\r\n\r\n";
+
+        assert_eq!(get_query_param(request, "brand").as_deref(), Some("Alfa Romeo"));
+        assert_eq!(get_query_param(request, "model").as_deref(), Some("C/X"));
+        assert_eq!(get_query_param(request, "bad").as_deref(), Some("%zz"));
+        assert_eq!(get_query_param(request, "year"), None);
+    }
+}
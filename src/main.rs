@@ -1,29 +1,68 @@
-use postgres::{ Client, NoTls };
+use postgres::{ CancelToken, Client, IsolationLevel, NoTls, Transaction };
 use postgres::Error as PostgresError;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::error::SqlState;
 use std::net::{ TcpListener, TcpStream };
-use std::io::{ Read, Write };
+use std::io::{ BufWriter, ErrorKind, Read, Write };
 use std::env;
+use std::time::{ Duration, Instant };
+use std::thread;
+use std::sync::mpsc::{ self, RecvTimeoutError };
+use std::sync::{ Arc, Mutex };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use socket2::{ SockRef, TcpKeepalive };
+use std::collections::hash_map::{ DefaultHasher, RandomState };
+use std::hash::{ BuildHasher, Hash, Hasher };
+use rust_app::{ Car, check_framing, is_request_complete, match_route, split_head };
+use rust_app::{ decode_query_value, get_header, get_path, get_query_param, get_route_path };
 
-#[macro_use]
-extern crate serde_derive;
-
-//Model: Car struct with id, brand, model, year, price
-#[derive(Serialize, Deserialize)]
-struct Car {
-    id: Option<i32>,
-    brand: String,
-    model: String,
-    year: i32,
-    price: f64,
-}
+//columns read for a car, the last one is its age in years with future model years as 0
+const CAR_COLUMNS: &str =
+    "id, brand, model, year, price, GREATEST(EXTRACT(YEAR FROM CURRENT_DATE)::INT - year, 0)";
 
 //DATABASE URL
 const DB_URL: &str = env!("DATABASE_URL");
 
+//successful writes within DEDUP_WINDOW_MS, keyed by a hash of method, path and body
+type RecentWrites = HashMap<u64, (Instant, (String, String))>;
+static RECENT_WRITES: Mutex<Option<RecentWrites>> = Mutex::new(None);
+
+//state of a handler running on a worker thread
+#[derive(Default)]
+struct Worker {
+    abandoned: bool,
+    cancel_tokens: Vec<CancelToken>,
+}
+
+thread_local! {
+    //set on worker threads by run_with_timeout
+    static WORKER: RefCell<Option<Arc<Mutex<Worker>>>> = const { RefCell::new(None) };
+}
+
+//largest request read, headers and body together
+const MAX_REQUEST_BYTES: usize = 1024 * 1024;
+
+//advisory lock key held while setting up the database
+const MIGRATION_LOCK_KEY: i64 = 6001;
+
 //constants
 const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
+const NDJSON_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\r\n";
+const NO_CONTENT: &str = "HTTP/1.1 204 NO CONTENT\r\n\r\n";
+const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\n\r\n";
+const FORBIDDEN: &str = "HTTP/1.1 403 FORBIDDEN\r\n\r\n";
 const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
+const REQUEST_TIMEOUT: &str = "HTTP/1.1 408 REQUEST TIMEOUT\r\n\r\n";
+const CONFLICT: &str = "HTTP/1.1 409 CONFLICT\r\n\r\n";
+const PRECONDITION_FAILED: &str = "HTTP/1.1 412 PRECONDITION FAILED\r\n\r\n";
+const PAYLOAD_TOO_LARGE: &str = "HTTP/1.1 413 PAYLOAD TOO LARGE\r\n\r\n";
+const URI_TOO_LONG: &str = "HTTP/1.1 414 URI TOO LONG\r\n\r\n";
+const UNPROCESSABLE_ENTITY: &str = "HTTP/1.1 422 UNPROCESSABLE ENTITY\r\n\r\n";
 const INTERNAL_ERROR: &str = "HTTP/1.1 500 INTERNAL ERROR\r\n\r\n";
+const SERVICE_UNAVAILABLE: &str = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\n\r\n";
+const GATEWAY_TIMEOUT: &str = "HTTP/1.1 504 GATEWAY TIMEOUT\r\n\r\n";
 
 //main function
 fn main() {
@@ -34,7 +73,7 @@ fn main() {
     }
 
     //start server and print port
-    let listener = TcpListener::bind(format!("0.0.0.0:6001")).unwrap();
+    let listener = TcpListener::bind("0.0.0.0:6001").unwrap();
     println!("Server listening on port 6001");
 
     for stream in listener.incoming() {
@@ -51,40 +90,356 @@ fn main() {
 
 //handle requests
 fn handle_client(mut stream: TcpStream) {
+    if let Err(e) = set_socket_options(&stream) {
+        eprintln!("Unable to set socket options: {}", e);
+    }
+
+    let max_uri_bytes = env::var("MAX_URI_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(2048);
+
+    match read_request(&mut stream, max_uri_bytes) {
+        Ok(bytes) => {
+            let request = String::from_utf8_lossy(&bytes);
+
+            let (status_line, content) = if is_uri_too_long(&bytes, max_uri_bytes) {
+                (URI_TOO_LONG.to_string(), "URI too long".to_string())
+            } else if is_request_too_large(&bytes) {
+                (PAYLOAD_TOO_LARGE.to_string(), "Request too large".to_string())
+            } else {
+                match check_framing(&bytes) {
+                    Ok(()) if is_ndjson_request(&request) => {
+                        if let Err(e) = write_cars_ndjson(&mut stream, includes_age(&request)) {
+                            eprintln!("Unable to write stream: {}", e);
+                        }
+
+                        return;
+                    }
+                    Ok(()) => route_request(&request),
+                    Err(message) => (BAD_REQUEST.to_string(), message.to_string()),
+                }
+            };
+
+            if let Err(e) = stream.write_all(format!("{}{}", status_line, content).as_bytes()) {
+                eprintln!("Unable to write stream: {}", e);
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            if let Err(e) = stream.write_all(format!("{}Request timeout", REQUEST_TIMEOUT).as_bytes()) {
+                eprintln!("Unable to write stream: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Unable to read stream: {}", e),
+    }
+}
+
+//read until the header block and the declared body have arrived, a request can span many
+//segments; bounded by MAX_REQUEST_BYTES and a READ_TIMEOUT_MS (default 5000) deadline for the
+//whole request, so a client trickling bytes can't hold the server; stops early once the URI is
+//known to be over max_uri_bytes or the headers declare a body that won't fit
+fn read_request(stream: &mut TcpStream, max_uri_bytes: usize) -> std::io::Result<Vec<u8>> {
+    let read_timeout = env::var("READ_TIMEOUT_MS")
+        .ok()
+        .and_then(|millis| millis.parse().ok())
+        .unwrap_or(5000);
+    let deadline = Instant::now() + Duration::from_millis(read_timeout);
+
+    let mut request = Vec::new();
     let mut buffer = [0; 1024];
-    let mut request = String::new();
-
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            request.push_str(String::from_utf8_lossy(&buffer[..size]).as_ref());
-
-            let (status_line, content) = match &*request {
-                r if r.starts_with("POST /cars") => handle_post_request(r),
-                r if r.starts_with("GET /cars/") => handle_get_request(r),
-                r if r.starts_with("GET /cars") => handle_get_all_request(r),
-                r if r.starts_with("PUT /cars/") => handle_put_request(r),
-                r if r.starts_with("DELETE /cars/") => handle_delete_request(r),
+
+    while
+        !is_request_complete(&request) &&
+        !is_request_too_large(&request) &&
+        !is_uri_too_long(&request, max_uri_bytes)
+    {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(ErrorKind::TimedOut, "request not received in time"));
+        }
+
+        stream.set_read_timeout(Some(remaining))?;
+        let size = stream.read(&mut buffer)?;
+
+        //client closed its side, nothing more is coming
+        if size == 0 {
+            break;
+        }
+
+        request.extend_from_slice(&buffer[..size]);
+    }
+
+    Ok(request)
+}
+
+//whether the request target is longer than max_uri_bytes, works on a partly read request line
+fn is_uri_too_long(request: &[u8], max_uri_bytes: usize) -> bool {
+    request
+        .split(|byte| *byte == b'\n')
+        .next()
+        .unwrap_or_default()
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|part| !part.is_empty())
+        .nth(1)
+        .map(|uri| uri.len() > max_uri_bytes)
+        .unwrap_or_default()
+}
+
+//whether the request is over MAX_REQUEST_BYTES, or its headers declare a body that will take it over
+fn is_request_too_large(request: &[u8]) -> bool {
+    if request.len() > MAX_REQUEST_BYTES {
+        return true;
+    }
+
+    if !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        return false;
+    }
+
+    let (headers, body) = split_head(request);
+
+    match get_header(&headers, "Content-Length").map(|length| length.parse::<usize>()) {
+        Some(Ok(length)) => (request.len() - body.len()).saturating_add(length) > MAX_REQUEST_BYTES,
+        _ => false,
+    }
+}
+
+//handler for a request
+type Handler = fn(&str) -> (String, String);
+
+//match request to its route template and handler
+fn get_route(request: &str) -> Option<(&'static str, Handler)> {
+    let route = match_route(request)?;
+    let handler: Handler = match route {
+        "OPTIONS" => handle_options_request,
+        "POST /cars" => handle_post_request,
+        "GET /cars/{id}/exists" => handle_exists_request,
+        "GET /cars/facets" => handle_facets_request,
+        "GET /cars/price-distribution" => handle_price_distribution_request,
+        "GET /cars/top" => handle_top_request,
+        "GET /cars/{id}" => handle_get_request,
+        "GET /cars" => handle_get_all_request,
+        "GET /admin/selftest" => handle_selftest_request,
+        "PATCH /cars" => handle_bulk_patch_request,
+        "PUT /cars/{id}" => handle_put_request,
+        "DELETE /cars/{id}" => handle_delete_request,
+        _ => {
+            return None;
+        }
+    };
+
+    Some((route, handler))
+}
+
+//TCP_NODELAY unless TCP_NODELAY=false, keepalive probes after TCP_KEEPALIVE_SECS idle (0 disables)
+fn set_socket_options(stream: &TcpStream) -> std::io::Result<()> {
+    stream.set_nodelay(env::var("TCP_NODELAY").map_or(true, |nodelay| nodelay != "false"))?;
+
+    let keepalive_secs = env::var("TCP_KEEPALIVE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(60);
+    let socket = SockRef::from(stream);
+
+    if keepalive_secs == 0 {
+        socket.set_keepalive(false)
+    } else {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(keepalive_secs)))
+    }
+}
+
+//route request to its handler
+fn route_request(request: &str) -> (String, String) {
+    match get_route(request) {
+        Some((route, _)) if is_route_disabled(route) => {
+            //disabled routes answer 404 unless DISABLED_ROUTES_STATUS asks for 403
+            match env::var("DISABLED_ROUTES_STATUS").as_deref() {
+                Ok("403") => (FORBIDDEN.to_string(), "Route disabled".to_string()),
                 _ => (NOT_FOUND.to_string(), "404 not found".to_string()),
+            }
+        }
+        Some((route, handler)) if is_write_request(request) => run_deduplicated(route, handler, request),
+        Some((route, handler)) => run_with_timeout(handler, request, get_handler_timeout(route)),
+        None => (NOT_FOUND.to_string(), "404 not found".to_string()),
+    }
+}
+
+//writes change data, so they are the ones worth de-duplicating
+fn is_write_request(request: &str) -> bool {
+    matches!(request.split_whitespace().next(), Some("POST" | "PUT" | "PATCH" | "DELETE"))
+}
+
+//with DEDUP_WINDOW_MS set, an identical write (see get_dedup_key) repeated within the window
+//gets the first one's successful response instead of running again
+fn run_deduplicated(route: &str, handler: Handler, request: &str) -> (String, String) {
+    let window = Duration::from_millis(
+        env::var("DEDUP_WINDOW_MS")
+            .ok()
+            .and_then(|millis| millis.parse().ok())
+            .unwrap_or(0)
+    );
+
+    if window.is_zero() {
+        return run_with_timeout(handler, request, get_handler_timeout(route));
+    }
+
+    let key = get_dedup_key(request);
+    let now = Instant::now();
+    let recent = RECENT_WRITES.lock().unwrap()
+        .get_or_insert_with(HashMap::new)
+        .get(&key)
+        .filter(|(at, _)| now.duration_since(*at) < window)
+        .map(|(_, response)| response.clone());
+
+    if let Some(response) = recent {
+        return response;
+    }
+
+    let response = run_with_timeout(handler, request, get_handler_timeout(route));
+
+    if response.0.starts_with("HTTP/1.1 2") {
+        let mut recent_writes = RECENT_WRITES.lock().unwrap();
+        let recent_writes = recent_writes.get_or_insert_with(HashMap::new);
+        recent_writes.retain(|_, (at, _)| now.duration_since(*at) < window);
+        recent_writes.insert(key, (now, response.clone()));
+    }
+
+    response
+}
+
+//what makes two writes identical: method, path, body and the headers that change what a write
+//does, a different If-Match or Prefer is a different request
+fn get_dedup_key(request: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.split_whitespace().next().hash(&mut hasher);
+    get_path(request).hash(&mut hasher);
+    get_header(request, "If-Match").hash(&mut hasher);
+    get_header(request, "Prefer").hash(&mut hasher);
+    request.split("\r\n\r\n").last().hash(&mut hasher);
+    hasher.finish()
+}
+
+//run handler on a worker thread, answering 504 if it doesn't finish within timeout; queries the
+//handler still has running are then cancelled and its transactions can no longer commit, a
+//commit already under way finishes before the 504 is sent
+fn run_with_timeout(handler: Handler, request: &str, timeout: Duration) -> (String, String) {
+    let (sender, receiver) = mpsc::channel();
+    let request = request.to_string();
+    let worker = Arc::new(Mutex::new(Worker::default()));
+    let handler_worker = Arc::clone(&worker);
+
+    thread::spawn(move || {
+        WORKER.with(|worker| *worker.borrow_mut() = Some(handler_worker));
+
+        //receiver is gone if the handler timed out, the result is dropped
+        let _ = sender.send(handler(&request));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(response) => response,
+        Err(RecvTimeoutError::Timeout) => {
+            let cancel_tokens = {
+                //waits for run_transaction to release the lock if it's committing
+                let mut worker = worker.lock().unwrap();
+                worker.abandoned = true;
+                std::mem::take(&mut worker.cancel_tokens)
             };
 
-            stream.write_all(format!("{}{}", status_line, content).as_bytes()).unwrap();
+            for cancel_token in cancel_tokens {
+                if let Err(e) = cancel_token.cancel_query(NoTls) {
+                    eprintln!("Unable to cancel query: {}", e);
+                }
+            }
+
+            (GATEWAY_TIMEOUT.to_string(), "Gateway timeout".to_string())
         }
-        Err(e) => eprintln!("Unable to read stream: {}", e),
+        Err(RecvTimeoutError::Disconnected) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//connect to the database, on a worker thread the connection is cancelled if the handler times out
+fn connect() -> Result<Client, PostgresError> {
+    let client = Client::connect(DB_URL, NoTls)?;
+
+    if let Some(worker) = get_worker() {
+        worker.lock().unwrap().cancel_tokens.push(client.cancel_token());
+    }
+
+    Ok(client)
+}
+
+//state of the handler running on this thread, None off worker threads
+fn get_worker() -> Option<Arc<Mutex<Worker>>> {
+    WORKER.with(|worker| worker.borrow().clone())
+}
+
+//timeout for route from ROUTE_TIMEOUTS, e.g. "GET /cars=5000,POST /cars=2000",
+//falling back to HANDLER_TIMEOUT_MS (default 30000)
+fn get_handler_timeout(route: &str) -> Duration {
+    let route_timeout = env::var("ROUTE_TIMEOUTS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .find(|(timeout_route, _)| timeout_route.trim() == route)
+        .and_then(|(_, millis)| millis.trim().parse().ok());
+    let millis = route_timeout
+        .or_else(|| env::var("HANDLER_TIMEOUT_MS").ok().and_then(|millis| millis.parse().ok()))
+        .unwrap_or(30000);
+
+    Duration::from_millis(millis)
+}
+
+//routes listed in DISABLED_ROUTES, e.g. "POST /cars,DELETE /cars/{id}"
+fn is_route_disabled(route: &str) -> bool {
+    env::var("DISABLED_ROUTES")
+        .unwrap_or_default()
+        .split(',')
+        .any(|disabled| disabled.trim() == route)
+}
+
+//handle options request, Allow lists the enabled methods routed for the path
+fn handle_options_request(request: &str) -> (String, String) {
+    let path = get_path(request);
+    let route_path = get_route_path(path);
+    let mut allowed: Vec<&str> = ["GET", "POST", "PUT", "PATCH", "DELETE"]
+        .into_iter()
+        .filter(|method| {
+            let route = format!("{} {}", method, route_path);
+
+            match get_route(&format!("{} {} HTTP/1.1\r\n\r\n", method, path)) {
+                Some((matched, _)) =>
+                    matched == route &&
+                        !is_route_disabled(matched) &&
+                        (matched != "GET /admin/selftest" || is_selftest_enabled()),
+                None => false,
+            }
+        })
+        .collect();
+
+    if allowed.is_empty() {
+        return (NOT_FOUND.to_string(), "404 not found".to_string());
     }
+
+    allowed.push("OPTIONS");
+
+    (with_header(NO_CONTENT, "Allow", &allowed.join(", ")), String::new())
 }
 
 //handle post request
 fn handle_post_request(request: &str) -> (String, String) {
-    match (get_car_request_body(&request), Client::connect(&*DB_URL, NoTls)) {
+    match (get_car_request_body(request), connect()) {
         (Ok(car), Ok(mut client)) => {
-            client
-                .execute(
+            let result = with_retryable_transaction(&mut client, request, |transaction| {
+                transaction.execute(
                     "INSERT INTO cars (brand, model, year, price) VALUES ($1, $2, $3, $4)",
                     &[&car.brand, &car.model, &car.year, &car.price]
                 )
-                .unwrap();
+            });
 
-            (OK_RESPONSE.to_string(), "Car created".to_string())
+            match result {
+                Ok(_) => (OK_RESPONSE.to_string(), "Car created".to_string()),
+                Err(e) => db_error_response(&e),
+            }
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
@@ -92,112 +447,1869 @@ fn handle_post_request(request: &str) -> (String, String) {
 
 //handle get request
 fn handle_get_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(&*DB_URL, NoTls)) {
+    match (get_id(request).parse::<i32>(), connect()) {
         (Ok(id), Ok(mut client)) =>
-            match client.query_one("SELECT * FROM cars WHERE id = $1", &[&id]) {
-                Ok(row) => {
-                    let car = Car {
+            match client.query_opt(&format!("SELECT {} FROM cars WHERE id = $1", CAR_COLUMNS), &[&id]) {
+                Ok(Some(row)) => {
+                    let mut car = Car {
                         id: row.get(0),
                         brand: row.get(1),
                         model: row.get(2),
                         year: row.get(3),
                         price: row.get(4),
+                        age: None,
+                    };
+
+                    //the ETag covers stored fields only
+                    let etag = get_etag(&car);
+
+                    if includes_age(request) {
+                        car.age = row.get(5);
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag), serde_json::to_string(&car).unwrap())
+                }
+                Ok(None) => (NOT_FOUND.to_string(), "Car not found".to_string()),
+                Err(e) => db_error_response(&e),
+            }
+
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle exists request
+fn handle_exists_request(request: &str) -> (String, String) {
+    let id = match get_id(request).parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => {
+            return (BAD_REQUEST.to_string(), "Invalid id".to_string());
+        }
+    };
+
+    match connect() {
+        Ok(mut client) =>
+            match client.query_one("SELECT EXISTS(SELECT 1 FROM cars WHERE id = $1)", &[&id]) {
+                Ok(row) => {
+                    let exists: bool = row.get(0);
+
+                    (OK_RESPONSE.to_string(), serde_json::json!({ "exists": exists }).to_string())
+                }
+                Err(e) => db_error_response(&e),
+            }
+
+        Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle facets request, distinct values with counts for text fields, min/max for numeric ones
+fn handle_facets_request(request: &str) -> (String, String) {
+    //field is checked against this list before it goes into the query
+    let field = get_query_param(request, "field").unwrap_or_default();
+    let field = field.as_str();
+    let query = match field {
+        "brand" | "model" =>
+            format!("SELECT {0}, COUNT(*) FROM cars GROUP BY {0} ORDER BY COUNT(*) DESC, {0}", field),
+        "year" | "price" => format!("SELECT MIN({0}), MAX({0}), COUNT(*) FROM cars", field),
+        _ => {
+            return (BAD_REQUEST.to_string(), "Invalid facet field".to_string());
+        }
+    };
+
+    match connect() {
+        Ok(mut client) =>
+            match client.query(&query, &[]) {
+                Ok(rows) => {
+                    let facet = match field {
+                        "year" =>
+                            serde_json::json!({
+                                "field": field,
+                                "min": rows[0].get::<_, Option<i32>>(0),
+                                "max": rows[0].get::<_, Option<i32>>(1),
+                                "count": rows[0].get::<_, i64>(2),
+                            }),
+                        "price" =>
+                            serde_json::json!({
+                                "field": field,
+                                "min": rows[0].get::<_, Option<f64>>(0),
+                                "max": rows[0].get::<_, Option<f64>>(1),
+                                "count": rows[0].get::<_, i64>(2),
+                            }),
+                        _ => {
+                            let values: Vec<_> = rows
+                                .iter()
+                                .map(|row| {
+                                    serde_json::json!({
+                                        "value": row.get::<_, String>(0),
+                                        "count": row.get::<_, i64>(1),
+                                    })
+                                })
+                                .collect();
+
+                            serde_json::json!({ "field": field, "values": values })
+                        }
                     };
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&car).unwrap())
+                    (OK_RESPONSE.to_string(), facet.to_string())
                 }
-                _ => (NOT_FOUND.to_string(), "Car not found".to_string()),
+                Err(e) => db_error_response(&e),
+            }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle price distribution request, percentiles and an equal-width histogram of prices,
+//optionally for one brand
+fn handle_price_distribution_request(request: &str) -> (String, String) {
+    let buckets = match get_query_param(request, "buckets").map(|buckets| buckets.parse::<i32>()) {
+        None => 10,
+        Some(Ok(buckets)) if (1..=100).contains(&buckets) => buckets,
+        _ => {
+            return (BAD_REQUEST.to_string(), "buckets must be between 1 and 100".to_string());
+        }
+    };
+    let brand = get_query_param(request, "brand");
+    let fractions: Vec<f64> = (1..buckets).map(|i| f64::from(i) / f64::from(buckets)).collect();
+
+    match connect() {
+        Ok(mut client) =>
+            match get_price_distribution(&mut client, buckets, brand.as_deref(), &fractions) {
+                Ok(distribution) => (OK_RESPONSE.to_string(), distribution.to_string()),
+                Err(e) => db_error_response(&e),
             }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//price percentiles and histogram computed in SQL, both from one snapshot so the bucket counts
+//add up to the total even while cars are being written
+fn get_price_distribution(
+    client: &mut Client,
+    buckets: i32,
+    brand: Option<&str>,
+    fractions: &[f64]
+) -> Result<serde_json::Value, PostgresError> {
+    let mut transaction = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()?;
+    let row = transaction.query_one(
+        "SELECT COUNT(*), MIN(price), MAX(price), percentile_cont($1::FLOAT[]) WITHIN GROUP (ORDER BY price)
+        FROM cars WHERE ($2::VARCHAR IS NULL OR brand = $2)",
+        &[&fractions, &brand]
+    )?;
+    let total: i64 = row.get(0);
+    let min: Option<f64> = row.get(1);
+    let max: Option<f64> = row.get(2);
+    let prices: Vec<f64> = row.get::<_, Option<Vec<f64>>>(3).unwrap_or_default();
+
+    let percentiles: Vec<_> = fractions
+        .iter()
+        .zip(prices)
+        .map(|(fraction, price)| serde_json::json!({ "percentile": fraction, "price": price }))
+        .collect();
+
+    //width_bucket puts the max price in bucket n + 1, it belongs to the last bucket
+    let rows = transaction.query(
+        "WITH bounds AS (
+            SELECT MIN(price) AS low, MAX(price) AS high FROM cars WHERE ($2::VARCHAR IS NULL OR brand = $2)
+        )
+        SELECT CASE WHEN high = low THEN 1 ELSE LEAST(width_bucket(price, low, high, $1), $1) END AS bucket,
+            COUNT(*)
+        FROM cars, bounds WHERE ($2::VARCHAR IS NULL OR brand = $2)
+        GROUP BY bucket ORDER BY bucket",
+        &[&buckets, &brand]
+    )?;
+    transaction.commit()?;
+    let mut counts = vec![0i64; buckets as usize];
+
+    for row in rows {
+        let bucket: i32 = row.get(0);
+        counts[(bucket - 1) as usize] = row.get(1);
+    }
+
+    let histogram: Vec<_> = match (min, max) {
+        (Some(min), Some(max)) => {
+            let width = (max - min) / f64::from(buckets);
+
+            counts
+                .iter()
+                .enumerate()
+                .map(|(i, count)| {
+                    serde_json::json!({
+                        "from": min + width * i as f64,
+                        "to": min + width * (i + 1) as f64,
+                        "count": count,
+                    })
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    Ok(serde_json::json!({
+        "total": total,
+        "min": min,
+        "max": max,
+        "percentiles": percentiles,
+        "buckets": histogram,
+    }))
+}
+
+//handle top request, the N cars with the highest or lowest price or year
+fn handle_top_request(request: &str) -> (String, String) {
+    //by and order are checked against these lists before they go into the query
+    let by = get_query_param(request, "by").unwrap_or_else(|| "price".to_string());
+    let order = get_query_param(request, "order").unwrap_or_else(|| "desc".to_string());
+    let limit = get_query_param(request, "limit").map_or(Ok(5), |limit| limit.parse::<i64>());
+
+    if by != "price" && by != "year" {
+        return (BAD_REQUEST.to_string(), "by must be price or year".to_string());
+    }
+
+    if order != "asc" && order != "desc" {
+        return (BAD_REQUEST.to_string(), "order must be asc or desc".to_string());
+    }
 
+    let limit = match limit {
+        Ok(limit) if (1..=100).contains(&limit) => limit,
+        _ => {
+            return (BAD_REQUEST.to_string(), "limit must be between 1 and 100".to_string());
+        }
+    };
+
+    match connect() {
+        Ok(mut client) => {
+            let query = format!("SELECT {} FROM cars ORDER BY {} {}, id LIMIT $1", CAR_COLUMNS, by, order);
+
+            match client.query(&query, &[&limit]) {
+                Ok(rows) => {
+                    let include_age = includes_age(request);
+                    let mut cars = Vec::new();
+
+                    for row in rows {
+                        cars.push(Car {
+                            id: row.get(0),
+                            brand: row.get(1),
+                            model: row.get(2),
+                            year: row.get(3),
+                            price: row.get(4),
+                            age: if include_age { row.get(5) } else { None },
+                        });
+                    }
+
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&cars).unwrap())
+                }
+                Err(e) => db_error_response(&e),
+            }
+        }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle get all request
-fn handle_get_all_request(_request: &str) -> (String, String) {
-    match Client::connect(&*DB_URL, NoTls) {
+fn handle_get_all_request(request: &str) -> (String, String) {
+    match connect() {
         Ok(mut client) => {
-            let mut cars = Vec::new();
+            match client.query(&format!("SELECT {} FROM cars", CAR_COLUMNS), &[]) {
+                Ok(rows) => {
+                    let include_age = includes_age(request);
+                    let mut cars = Vec::new();
+
+                    for row in rows {
+                        cars.push(Car {
+                            id: row.get(0),
+                            brand: row.get(1),
+                            model: row.get(2),
+                            year: row.get(3),
+                            price: row.get(4),
+                            age: if include_age { row.get(5) } else { None },
+                        });
+                    }
+
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&cars).unwrap())
+                }
+                Err(e) => db_error_response(&e),
+            }
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//age is added with ?include=age, or always with INCLUDE_AGE=true
+fn includes_age(request: &str) -> bool {
+    env::var("INCLUDE_AGE").as_deref() == Ok("true") ||
+        get_query_param(request, "include")
+            .unwrap_or_default()
+            .split(',')
+            .any(|field| field == "age")
+}
+
+//GET /cars asking for newline-delimited JSON, handle_client streams it with write_cars_ndjson
+//instead of routing it
+fn is_ndjson_request(request: &str) -> bool {
+    matches!(get_route(request), Some(("GET /cars", _))) &&
+        !is_route_disabled("GET /cars") &&
+        get_header(request, "Accept").unwrap_or_default().contains("application/x-ndjson")
+}
+
+//all cars as newline-delimited JSON, one car per line written to stream as its row comes off
+//query_raw, so the table is never held in memory; the handler timeout doesn't apply, once rows
+//are flowing the 200 is already sent
+fn write_cars_ndjson(stream: &mut impl Write, include_age: bool) -> std::io::Result<()> {
+    let mut client = match connect() {
+        Ok(client) => client,
+        Err(_) => {
+            return stream.write_all(format!("{}Internal error", INTERNAL_ERROR).as_bytes());
+        }
+    };
+    let params: [i32; 0] = [];
+    let mut rows = match client.query_raw(&format!("SELECT {} FROM cars", CAR_COLUMNS), params) {
+        Ok(rows) => rows,
+        Err(e) => {
+            let (status_line, content) = db_error_response(&e);
+            return stream.write_all(format!("{}{}", status_line, content).as_bytes());
+        }
+    };
+    let mut stream = BufWriter::new(stream);
+    stream.write_all(NDJSON_RESPONSE.as_bytes())?;
 
-            for row in client.query("SELECT id, brand, model, year, price FROM cars", &[]).unwrap() {
-                cars.push(Car {
+    loop {
+        match rows.next() {
+            Ok(Some(row)) => {
+                let car = Car {
                     id: row.get(0),
                     brand: row.get(1),
                     model: row.get(2),
                     year: row.get(3),
                     price: row.get(4),
-                });
-            }
+                    age: if include_age { row.get(5) } else { None },
+                };
 
-            (OK_RESPONSE.to_string(), serde_json::to_string(&cars).unwrap())
+                serde_json::to_writer(&mut stream, &car)?;
+                stream.write_all(b"\n")?;
+            }
+            Ok(None) => break,
+            //the 200 is already out, ending the body early is all that's left
+            Err(e) => {
+                eprintln!("Unable to read cars: {}", e);
+                break;
+            }
         }
-        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
+
+    stream.flush()
 }
 
 //handle put request
 fn handle_put_request(request: &str) -> (String, String) {
     match
         (
-            get_id(&request).parse::<i32>(),
-            get_car_request_body(&request),
-            Client::connect(&*DB_URL, NoTls),
+            get_id(request).parse::<i32>(),
+            get_car_request_body(request),
+            connect(),
         )
     {
         (Ok(id), Ok(car), Ok(mut client)) => {
-            client
-                .execute(
+            let result = with_retryable_transaction(&mut client, request, |transaction| {
+                transaction.execute(
                     "UPDATE cars SET brand = $1, model = $2, year = $3, price = $4 WHERE id = $5",
                     &[&car.brand, &car.model, &car.year, &car.price, &id]
                 )
-                .unwrap();
+            });
 
-            (OK_RESPONSE.to_string(), "Car updated".to_string())
+            match result {
+                Ok(_) => (OK_RESPONSE.to_string(), "Car updated".to_string()),
+                Err(e) => db_error_response(&e),
+            }
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle delete request
-fn handle_delete_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(&*DB_URL, NoTls)) {
-        (Ok(id), Ok(mut client)) => {
-            let rows_affected = client.execute("DELETE FROM cars WHERE id = $1", &[&id]).unwrap();
+//handle bulk patch request, sets the body's fields on every car matching the query filters
+fn handle_bulk_patch_request(request: &str) -> (String, String) {
+    let fields: serde_json::Map<String, serde_json::Value> = match
+        serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+    {
+        Ok(fields) => fields,
+        Err(_) => {
+            return (BAD_REQUEST.to_string(), "Invalid body".to_string());
+        }
+    };
+    let mut params: Vec<Box<dyn ToSql + Sync>> = Vec::new();
+    let mut assignments = Vec::new();
+    let mut filters = Vec::new();
+
+    for (field, value) in &fields {
+        let param: Option<Box<dyn ToSql + Sync>> = match field.as_str() {
+            "brand" | "model" => value.as_str().map(|value| Box::new(value.to_string()) as _),
+            "year" =>
+                value
+                    .as_i64()
+                    .and_then(|value| i32::try_from(value).ok())
+                    .map(|value| Box::new(value) as _),
+            "price" => value.as_f64().map(|value| Box::new(value) as _),
+            _ => None,
+        };
 
-            //if rows affected is 0, car not found
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "Car not found".to_string());
+        match param {
+            Some(param) => {
+                params.push(param);
+                assignments.push(format!("{} = ${}", field, params.len()));
             }
+            None => {
+                return (BAD_REQUEST.to_string(), format!("Invalid field {}", field));
+            }
+        }
+    }
+
+    //an unsupported filter would otherwise be ignored and the update reach more cars than meant
+    let query = get_path(request).split_once('?').map(|(_, query)| query).unwrap_or_default();
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let key = decode_query_value(pair.split('=').next().unwrap_or_default());
+
+        if !matches!(key.as_str(), "brand" | "model" | "year" | "confirm") {
+            return (BAD_REQUEST.to_string(), format!("Invalid filter {}", key));
+        }
+    }
+
+    for field in ["brand", "model", "year"] {
+        if let Some(value) = get_query_param(request, field) {
+            let param: Box<dyn ToSql + Sync> = match field {
+                "year" =>
+                    match value.parse::<i32>() {
+                        Ok(year) => Box::new(year),
+                        Err(_) => {
+                            return (BAD_REQUEST.to_string(), "Invalid year filter".to_string());
+                        }
+                    }
+                _ => Box::new(value),
+            };
 
-            (OK_RESPONSE.to_string(), "Car deleted".to_string())
+            params.push(param);
+            filters.push(format!("{} = ${}", field, params.len()));
+        }
+    }
+
+    //an empty filter would update the whole table, so it must be asked for explicitly
+    if filters.is_empty() && get_query_param(request, "confirm").as_deref() != Some("true") {
+        return (
+            BAD_REQUEST.to_string(),
+            "Filter required, or confirm=true to update all cars".to_string(),
+        );
+    }
+
+    if assignments.is_empty() {
+        return (BAD_REQUEST.to_string(), "No fields to update".to_string());
+    }
+
+    let mut query = format!("UPDATE cars SET {}", assignments.join(", "));
+
+    if !filters.is_empty() {
+        query.push_str(&format!(" WHERE {}", filters.join(" AND ")));
+    }
+
+    match connect() {
+        Ok(mut client) => {
+            let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|param| param.as_ref()).collect();
+            let result = with_retryable_transaction(&mut client, request, |transaction| {
+                transaction.execute(&query, &params)
+            });
+
+            match result {
+                Ok(rows_affected) =>
+                    (OK_RESPONSE.to_string(), serde_json::json!({ "updated": rows_affected }).to_string()),
+                Err(e) => db_error_response(&e),
+            }
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//db setup
-fn set_database() -> Result<(), PostgresError> {
-    let mut client = Client::connect(&*DB_URL, NoTls)?;
-    client.batch_execute(
-        "
-        CREATE TABLE IF NOT EXISTS cars (
-            id SERIAL PRIMARY KEY,
-            brand VARCHAR NOT NULL,
-            model VARCHAR NOT NULL,
-            year INT NOT NULL,
-            price FLOAT NOT NULL
+//the server has no authentication, so SELFTEST_ENABLED=true is the only thing keeping the selftest
+//from anyone who can reach the port
+fn is_selftest_enabled() -> bool {
+    env::var("SELFTEST_ENABLED").as_deref() == Ok("true")
+}
+
+//handle selftest request, only served when the selftest is enabled
+fn handle_selftest_request(_request: &str) -> (String, String) {
+    if !is_selftest_enabled() {
+        return (NOT_FOUND.to_string(), "404 not found".to_string());
+    }
+
+    match connect() {
+        Ok(mut client) => {
+            let mut steps = Vec::new();
+            let passed = run_selftest(&mut client, &mut steps).is_ok();
+            let status_line = if passed { OK_RESPONSE } else { INTERNAL_ERROR };
+
+            (status_line.to_string(), serde_json::json!({ "passed": passed, "steps": steps }).to_string())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//create, read, update and delete a throwaway car inside a transaction that is rolled back
+fn run_selftest(client: &mut Client, steps: &mut Vec<serde_json::Value>) -> Result<(), PostgresError> {
+    let mut transaction = client.transaction()?;
+
+    let row = record_step(
+        steps,
+        "create",
+        transaction.query_one(
+            "INSERT INTO cars (brand, model, year, price) VALUES ($1, $2, $3, $4) RETURNING id",
+            &[&"selftest", &"selftest", &2000, &1.0]
         )
-    "
     )?;
-    Ok(())
+    let id: i32 = row.get(0);
+
+    record_step(steps, "read", transaction.query_one("SELECT * FROM cars WHERE id = $1", &[&id]))?;
+    record_step(
+        steps,
+        "update",
+        transaction.execute("UPDATE cars SET price = $1 WHERE id = $2", &[&2.0, &id])
+    )?;
+    record_step(steps, "delete", transaction.execute("DELETE FROM cars WHERE id = $1", &[&id]))?;
+
+    transaction.rollback()
 }
 
-//Get id from request URL
-fn get_id(request: &str) -> &str {
-    request.split("/").nth(2).unwrap_or_default().split_whitespace().next().unwrap_or_default()
+//record the outcome of a selftest step
+fn record_step<T>(
+    steps: &mut Vec<serde_json::Value>,
+    step: &str,
+    result: Result<T, PostgresError>
+) -> Result<T, PostgresError> {
+    steps.push(match &result {
+        Ok(_) => serde_json::json!({ "step": step, "passed": true }),
+        Err(e) => serde_json::json!({ "step": step, "passed": false, "error": e.to_string() }),
+    });
+
+    result
 }
 
-//deserialize car from request body without id
-fn get_car_request_body(request: &str) -> Result<Car, serde_json::Error> {
-    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+//handle delete request
+fn handle_delete_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), connect()) {
+        (Ok(id), Ok(mut client)) => {
+            let result = with_retryable_transaction(&mut client, request, |transaction| {
+                //if If-Match is sent, only delete when the car is unchanged since the client read it
+                if let Some(if_match) = get_header(request, "If-Match") {
+                    let row = transaction.query_opt(
+                        "SELECT id, brand, model, year, price FROM cars WHERE id = $1 FOR UPDATE",
+                        &[&id]
+                    )?;
+
+                    match row {
+                        Some(row) => {
+                            let car = Car {
+                                id: row.get(0),
+                                brand: row.get(1),
+                                model: row.get(2),
+                                year: row.get(3),
+                                price: row.get(4),
+                                age: None,
+                            };
+                            let etag = get_etag(&car);
+
+                            if if_match != "*" && !if_match.split(',').any(|tag| tag.trim() == etag) {
+                                return Ok((
+                                    PRECONDITION_FAILED.to_string(),
+                                    "Car has been modified".to_string(),
+                                ));
+                            }
+                        }
+                        //there is no current representation for If-Match to match
+                        None => {
+                            return Ok((PRECONDITION_FAILED.to_string(), "Car not found".to_string()));
+                        }
+                    }
+                }
+
+                let rows_affected = transaction.execute("DELETE FROM cars WHERE id = $1", &[&id])?;
+
+                //if rows affected is 0, car not found
+                if rows_affected == 0 {
+                    return Ok((NOT_FOUND.to_string(), "Car not found".to_string()));
+                }
+
+                Ok((OK_RESPONSE.to_string(), "Car deleted".to_string()))
+            });
+
+            result.unwrap_or_else(|e| db_error_response(&e))
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//run body in a transaction, committed only if it succeeds; serialization failures and deadlocks
+//rerun it up to TRANSACTION_RETRIES times (default 3) after a short jittered backoff
+fn with_retryable_transaction<T>(
+    client: &mut Client,
+    request: &str,
+    mut body: impl FnMut(&mut Transaction) -> Result<T, PostgresError>
+) -> Result<T, PostgresError> {
+    let retries = env::var("TRANSACTION_RETRIES")
+        .ok()
+        .and_then(|retries| retries.parse().ok())
+        .unwrap_or(3);
+    let mut attempt: u32 = 0;
+
+    loop {
+        match run_transaction(client, request, &mut body) {
+            Err(e) if attempt < retries && is_retryable(&e) => {
+                attempt += 1;
+
+                //10ms, 20ms, 40ms... plus up to 10ms of jitter so retries don't collide again
+                let jitter = RandomState::new().build_hasher().finish() % 10;
+                thread::sleep(Duration::from_millis((10u64 << (attempt - 1).min(6)) + jitter));
+            }
+            result => {
+                return result;
+            }
+        }
+    }
+}
+
+//run body once in a transaction
+fn run_transaction<T>(
+    client: &mut Client,
+    request: &str,
+    body: &mut impl FnMut(&mut Transaction) -> Result<T, PostgresError>
+) -> Result<T, PostgresError> {
+    let mut transaction = client.transaction()?;
+    set_durability(&mut transaction, request)?;
+    let result = body(&mut transaction)?;
+
+    //held until the commit is done, so a timeout can't be declared between the check and the commit
+    let worker = get_worker();
+    let worker = worker.as_ref().map(|worker| worker.lock().unwrap());
+
+    //the client was already told 504, its changes must not land afterwards
+    if worker.as_ref().map(|worker| worker.abandoned).unwrap_or_default() {
+        transaction.batch_execute(
+            "DO $$ BEGIN RAISE EXCEPTION 'handler timed out' USING ERRCODE = 'query_canceled'; END $$"
+        )?;
+    }
+
+    transaction.commit()?;
+    Ok(result)
+}
+
+//errors that a rerun of the same transaction can succeed past
+fn is_retryable(error: &PostgresError) -> bool {
+    match error.code() {
+        Some(code) => *code == SqlState::T_R_SERIALIZATION_FAILURE || *code == SqlState::T_R_DEADLOCK_DETECTED,
+        None => false,
+    }
+}
+
+//map a database error to a response by its SQLSTATE
+fn db_error_response(error: &PostgresError) -> (String, String) {
+    let message = error.as_db_error().map(|e| e.message().to_string()).unwrap_or_default();
+
+    match error.code() {
+        Some(code) if *code == SqlState::UNIQUE_VIOLATION || *code == SqlState::FOREIGN_KEY_VIOLATION =>
+            (CONFLICT.to_string(), message),
+        Some(code) if *code == SqlState::CHECK_VIOLATION || *code == SqlState::NOT_NULL_VIOLATION =>
+            (UNPROCESSABLE_ENTITY.to_string(), message),
+        //still failing after with_retryable_transaction's retries
+        Some(code) if *code == SqlState::T_R_SERIALIZATION_FAILURE =>
+            (CONFLICT.to_string(), "Conflicting concurrent update, try again".to_string()),
+        Some(code) if *code == SqlState::T_R_DEADLOCK_DETECTED =>
+            (with_header(SERVICE_UNAVAILABLE, "Retry-After", "1"), "Deadlock, try again".to_string()),
+        _ => {
+            eprintln!("Database error: {}", error);
+            (INTERNAL_ERROR.to_string(), "Internal error".to_string())
+        }
+    }
+}
+
+//Prefer: durability=strict waits for the commit to be flushed, durability=relaxed doesn't,
+//otherwise the server's synchronous_commit applies
+fn set_durability(transaction: &mut Transaction, request: &str) -> Result<(), PostgresError> {
+    let durability = get_header(request, "Prefer")
+        .unwrap_or_default()
+        .split(',')
+        .find_map(|preference| preference.trim().strip_prefix("durability="));
+
+    match durability {
+        Some("strict") => transaction.batch_execute("SET LOCAL synchronous_commit = on"),
+        Some("relaxed") => transaction.batch_execute("SET LOCAL synchronous_commit = off"),
+        _ => Ok(()),
+    }
+}
+
+//db setup
+fn set_database() -> Result<(), PostgresError> {
+    let mut client = Client::connect(DB_URL, NoTls)?;
+    migrate(&mut client)
+}
+
+//replicas starting at once wait here so only one runs the setup at a time
+fn migrate(client: &mut Client) -> Result<(), PostgresError> {
+    client.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY])?;
+    let result = create_schema(client);
+    client.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY])?;
+    result
+}
+
+//create tables and indexes
+fn create_schema(client: &mut Client) -> Result<(), PostgresError> {
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS cars (
+            id SERIAL PRIMARY KEY,
+            brand VARCHAR NOT NULL,
+            model VARCHAR NOT NULL,
+            year INT NOT NULL,
+            price FLOAT NOT NULL
+        )
+    "
+    )?;
+
+    //indexes for the commonly filtered columns, (brand, year) also serves brand alone,
+    //CREATE_INDEXES=false skips them on small tables
+    if env::var("CREATE_INDEXES").as_deref() != Ok("false") {
+        client.batch_execute(
+            "
+            CREATE INDEX IF NOT EXISTS cars_year_idx ON cars (year);
+            CREATE INDEX IF NOT EXISTS cars_price_idx ON cars (price);
+            CREATE INDEX IF NOT EXISTS cars_brand_year_idx ON cars (brand, year);
+        "
+        )?;
+    }
+
+    Ok(())
+}
+
+//add a header to a status line
+fn with_header(status_line: &str, name: &str, value: &str) -> String {
+    format!("{}\r\n{}: {}\r\n\r\n", status_line.trim_end_matches("\r\n"), name, value)
+}
+
+//ETag of a car, changes whenever any of its fields change
+fn get_etag(car: &Car) -> String {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(car).unwrap().hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
+
+//Get id from request URL
+fn get_id(request: &str) -> &str {
+    request
+        .split("/")
+        .nth(2)
+        .unwrap_or_default()
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+}
+
+//deserialize car from request body without id
+fn get_car_request_body(request: &str) -> Result<Car, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    //tests marked #[ignore] use the DATABASE_URL database and share its cars table, run them with
+    //cargo test -- --ignored --test-threads=1
+
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    //tests reading or setting config env vars hold this so they don't see each other's settings
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //insert a car for a test and return its id, tests use their own brand and delete it after
+    fn insert_test_car(client: &mut Client, brand: &str, year: i32, price: f64) -> i32 {
+        client
+            .query_one(
+                "INSERT INTO cars (brand, model, year, price) VALUES ($1, 'm', $2, $3) RETURNING id",
+                &[&brand, &year, &price]
+            )
+            .unwrap()
+            .get(0)
+    }
+
+    fn delete_test_cars(client: &mut Client, brand: &str) {
+        client.execute("DELETE FROM cars WHERE brand = $1", &[&brand]).unwrap();
+    }
+
+    #[test]
+    fn exists_rejects_non_numeric_id() {
+        assert_eq!(handle_exists_request("GET /cars/abc/exists HTTP/1.1\r\n\r\n").0, BAD_REQUEST);
+    }
+
+    #[test]
+    fn exists_route_ignores_query_string() {
+        let _env = lock_env();
+        let route = get_route("GET /cars/1/exists?x=1 HTTP/1.1\r\n\r\n").map(|(route, _)| route);
+
+        assert_eq!(route, Some("GET /cars/{id}/exists"));
+        assert_eq!(allowed_methods("/cars/1/exists?x=1").as_deref(), Some("GET, OPTIONS"));
+    }
+
+    #[test]
+    #[ignore]
+    fn exists_reports_existing_and_missing_cars() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let id = insert_test_car(&mut client, "test-exists", 2010, 1000.0);
+
+        let existing = handle_exists_request(&format!("GET /cars/{}/exists HTTP/1.1\r\n\r\n", id));
+        delete_test_cars(&mut client, "test-exists");
+        let missing = handle_exists_request(&format!("GET /cars/{}/exists HTTP/1.1\r\n\r\n", id));
+
+        assert_eq!(existing, (OK_RESPONSE.to_string(), r#"{"exists":true}"#.to_string()));
+        assert_eq!(missing, (OK_RESPONSE.to_string(), r#"{"exists":false}"#.to_string()));
+    }
+
+    fn count_test_cars(client: &mut Client, brand: &str) -> i64 {
+        client.query_one("SELECT COUNT(*) FROM cars WHERE brand = $1", &[&brand]).unwrap().get(0)
+    }
+
+    fn delete_request(id: i32, if_match: &str) -> String {
+        format!("DELETE /cars/{} HTTP/1.1\r\nIf-Match: {}\r\n\r\n", id, if_match)
+    }
+
+    #[test]
+    #[ignore]
+    fn delete_with_stale_etag_is_412() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let id = insert_test_car(&mut client, "test-delete-stale", 2010, 1000.0);
+
+        let (status_line, _) = handle_delete_request(&delete_request(id, "\"stale\""));
+        let remaining = count_test_cars(&mut client, "test-delete-stale");
+        delete_test_cars(&mut client, "test-delete-stale");
+
+        assert_eq!(status_line, PRECONDITION_FAILED);
+        assert_eq!(remaining, 1);
+    }
+
+    #[test]
+    #[ignore]
+    fn delete_with_current_etag_deletes() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let id = insert_test_car(&mut client, "test-delete-current", 2010, 1000.0);
+        let etag = get_etag(&Car {
+            id: Some(id),
+            brand: "test-delete-current".to_string(),
+            model: "m".to_string(),
+            year: 2010,
+            price: 1000.0,
+            age: None,
+        });
+
+        let (status_line, _) = handle_delete_request(&delete_request(id, &etag));
+        let remaining = count_test_cars(&mut client, "test-delete-current");
+        delete_test_cars(&mut client, "test-delete-current");
+
+        assert_eq!(status_line, OK_RESPONSE);
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn delete_missing_car_with_if_match_is_412() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let id = insert_test_car(&mut client, "test-delete-missing", 2010, 1000.0);
+        delete_test_cars(&mut client, "test-delete-missing");
+
+        assert_eq!(handle_delete_request(&delete_request(id, "*")).0, PRECONDITION_FAILED);
+        assert_eq!(handle_delete_request(&format!("DELETE /cars/{} HTTP/1.1\r\n\r\n", id)).0, NOT_FOUND);
+    }
+
+    #[test]
+    #[ignore]
+    fn setup_waits_for_the_migration_lock() {
+        let mut holder = Client::connect(DB_URL, NoTls).unwrap();
+        holder.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY]).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(set_database().is_ok()));
+        let while_held = receiver.recv_timeout(Duration::from_millis(500));
+
+        holder.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY]).unwrap();
+        let after_release = receiver.recv_timeout(Duration::from_secs(5));
+
+        assert_eq!(while_held, Err(RecvTimeoutError::Timeout));
+        assert_eq!(after_release, Ok(true));
+    }
+
+    //a client whose tables go to a fresh schema, so setup can run from scratch
+    fn connect_to_scratch_schema(schema: &str) -> Client {
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        client.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {0}; SET search_path TO {0}", schema)).unwrap();
+        client
+    }
+
+    fn drop_scratch_schema(schema: &str) {
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        client.batch_execute(&format!("DROP SCHEMA IF EXISTS {} CASCADE", schema)).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn concurrent_setups_on_an_empty_schema_all_succeed() {
+        let _env = lock_env();
+        drop_scratch_schema("test_migrate");
+        connect_to_scratch_schema("test_migrate");
+
+        //without the lock, racing CREATE TABLE IF NOT EXISTS can fail with a duplicate key
+        let setups: Vec<_> = (0..8)
+            .map(|_| thread::spawn(|| migrate(&mut connect_to_scratch_schema("test_migrate")).map_err(|e| e.to_string())))
+            .collect();
+        let results: Vec<_> = setups.into_iter().map(|setup| setup.join().unwrap()).collect();
+
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let tables: i64 = client
+            .query_one("SELECT COUNT(*) FROM pg_tables WHERE schemaname = 'test_migrate'", &[])
+            .unwrap()
+            .get(0);
+        drop_scratch_schema("test_migrate");
+
+        assert!(results.iter().all(|result| result.is_ok()), "{:?}", results);
+        assert_eq!(tables, 1);
+    }
+
+    const POST_REQUEST: &str = "POST /cars HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+
+    #[test]
+    fn disabled_post_answers_configured_status() {
+        let _env = lock_env();
+        env::set_var("DISABLED_ROUTES", "POST /cars");
+        let default_status = route_request(POST_REQUEST).0;
+        env::set_var("DISABLED_ROUTES_STATUS", "403");
+        let forbidden = route_request(POST_REQUEST).0;
+        env::remove_var("DISABLED_ROUTES_STATUS");
+        env::remove_var("DISABLED_ROUTES");
+
+        assert_eq!(default_status, NOT_FOUND);
+        assert_eq!(forbidden, FORBIDDEN);
+    }
+
+    #[test]
+    #[ignore]
+    fn get_still_works_with_post_disabled() {
+        let _env = lock_env();
+        set_database().unwrap();
+        env::set_var("DISABLED_ROUTES", "POST /cars");
+        let (status_line, body) = route_request("GET /cars HTTP/1.1\r\n\r\n");
+        env::remove_var("DISABLED_ROUTES");
+
+        assert_eq!(status_line, OK_RESPONSE);
+        assert!(body.starts_with('['));
+    }
+
+    #[test]
+    fn facets_reject_fields_off_the_list() {
+        for query in ["", "?field=id", "?field=brand%3B%20DROP%20TABLE%20cars", "?field=BRAND"] {
+            let request = format!("GET /cars/facets{} HTTP/1.1\r\n\r\n", query);
+            assert_eq!(handle_facets_request(&request), (BAD_REQUEST.to_string(), "Invalid facet field".to_string()));
+        }
+    }
+
+    fn get_facet(field: &str) -> serde_json::Value {
+        let (status_line, body) = handle_facets_request(&format!("GET /cars/facets?field={} HTTP/1.1\r\n\r\n", field));
+        assert_eq!(status_line, OK_RESPONSE);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn brand_facet_counts_each_brand() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+
+        for year in [2001, 2002, 2003] {
+            insert_test_car(&mut client, "test-facet-brand", year, 1000.0);
+        }
+
+        let facet = get_facet("brand");
+        delete_test_cars(&mut client, "test-facet-brand");
+
+        let values = facet["values"].as_array().unwrap();
+        assert_eq!(facet["field"], "brand");
+        assert!(values.contains(&serde_json::json!({ "value": "test-facet-brand", "count": 3 })));
+        assert!(values.windows(2).all(|pair| pair[0]["count"].as_i64() >= pair[1]["count"].as_i64()));
+    }
+
+    #[test]
+    #[ignore]
+    fn year_facet_has_min_max_and_count() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        insert_test_car(&mut client, "test-facet-year", 1901, 1000.0);
+        insert_test_car(&mut client, "test-facet-year", 2999, 1000.0);
+
+        let facet = get_facet("year");
+        let row = client.query_one("SELECT MIN(year), MAX(year), COUNT(*) FROM cars", &[]).unwrap();
+        delete_test_cars(&mut client, "test-facet-year");
+
+        assert_eq!(facet, serde_json::json!({
+            "field": "year",
+            "min": row.get::<_, i32>(0),
+            "max": row.get::<_, i32>(1),
+            "count": row.get::<_, i64>(2),
+        }));
+        assert!(facet["min"].as_i64() <= Some(1901) && facet["max"].as_i64() >= Some(2999));
+    }
+
+    #[test]
+    #[ignore]
+    fn ndjson_has_one_car_per_line() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        insert_test_car(&mut client, "test-ndjson", 2001, 100.0);
+        insert_test_car(&mut client, "test-ndjson", 2002, 200.0);
+
+        let response = serve_byte_by_byte(b"GET /cars HTTP/1.1\r\nAccept: application/x-ndjson\r\n\r\n");
+        let total: i64 = client.query_one("SELECT COUNT(*) FROM cars", &[]).unwrap().get(0);
+        delete_test_cars(&mut client, "test-ndjson");
+
+        let body = response.strip_prefix(NDJSON_RESPONSE).unwrap();
+        let cars: Vec<Car> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+
+        assert!(body.ends_with('\n'));
+        assert_eq!(cars.len() as i64, total);
+        assert_eq!(cars.iter().filter(|car| car.brand == "test-ndjson").count(), 2);
+    }
+
+    //records the size of every write it gets
+    struct ChunkWriter(Vec<usize>);
+
+    impl Write for ChunkWriter {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buffer.len());
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn ndjson_is_written_while_rows_are_read() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+
+        for year in 0..500 {
+            insert_test_car(&mut client, "test-ndjson-stream", 1500 + year, 100.0);
+        }
+
+        let mut writer = ChunkWriter(Vec::new());
+        write_cars_ndjson(&mut writer, false).unwrap();
+        delete_test_cars(&mut client, "test-ndjson-stream");
+
+        //the body goes out a buffer at a time, not as one write at the end
+        assert!(writer.0.len() > 1, "{:?}", writer.0);
+        assert!(writer.0.iter().all(|size| *size <= 8192), "{:?}", writer.0);
+    }
+
+    #[test]
+    fn only_ndjson_get_all_is_streamed() {
+        let _env = lock_env();
+        let ndjson = "GET /cars HTTP/1.1\r\nAccept: application/x-ndjson\r\n\r\n";
+        let streamed = is_ndjson_request(ndjson);
+        env::set_var("DISABLED_ROUTES", "GET /cars");
+        let disabled = is_ndjson_request(ndjson);
+        env::remove_var("DISABLED_ROUTES");
+
+        assert!(streamed);
+        assert!(!disabled);
+        assert!(!is_ndjson_request("GET /cars HTTP/1.1\r\nAccept: application/json\r\n\r\n"));
+        assert!(!is_ndjson_request("GET /cars/1 HTTP/1.1\r\nAccept: application/x-ndjson\r\n\r\n"));
+    }
+
+    #[test]
+    #[ignore]
+    fn prefer_durability_sets_synchronous_commit_for_the_transaction() {
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let show = |client: &mut Client| -> String {
+            client.query_one("SHOW synchronous_commit", &[]).unwrap().get(0)
+        };
+        let server_default = show(&mut client);
+
+        for (prefer, expected) in [
+            ("durability=strict", "on"),
+            ("respond-async, durability=relaxed", "off"),
+            ("durability=eventual", server_default.as_str()),
+            ("", server_default.as_str()),
+        ] {
+            let request = format!("POST /cars HTTP/1.1\r\nPrefer: {}\r\n\r\n", prefer);
+            let mut transaction = client.transaction().unwrap();
+            set_durability(&mut transaction, &request).unwrap();
+            let inside: String = transaction.query_one("SHOW synchronous_commit", &[]).unwrap().get(0);
+            transaction.commit().unwrap();
+
+            assert_eq!(inside, expected, "Prefer: {}", prefer);
+            //SET LOCAL ends with the transaction
+            assert_eq!(show(&mut client), server_default);
+        }
+    }
+
+    //nodelay and keepalive of an accepted loopback socket after set_socket_options
+    fn get_socket_options() -> (bool, bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        set_socket_options(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        (socket.nodelay().unwrap(), socket.keepalive().unwrap())
+    }
+
+    #[test]
+    fn socket_options_are_applied() {
+        let _env = lock_env();
+        env::remove_var("TCP_NODELAY");
+        env::remove_var("TCP_KEEPALIVE_SECS");
+        let defaults = get_socket_options();
+
+        env::set_var("TCP_NODELAY", "false");
+        env::set_var("TCP_KEEPALIVE_SECS", "0");
+        let disabled = get_socket_options();
+        env::remove_var("TCP_NODELAY");
+        env::remove_var("TCP_KEEPALIVE_SECS");
+
+        assert_eq!(defaults, (true, true));
+        assert_eq!(disabled, (false, false));
+    }
+
+    fn bulk_patch(query: &str, body: &str) -> (String, String) {
+        handle_bulk_patch_request(&format!(
+            "PATCH /cars{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            query,
+            body.len(),
+            body
+        ))
+    }
+
+    #[test]
+    fn bulk_patch_needs_filter_or_confirm() {
+        let guard = (BAD_REQUEST.to_string(), "Filter required, or confirm=true to update all cars".to_string());
+        let no_fields = (BAD_REQUEST.to_string(), "No fields to update".to_string());
+
+        assert_eq!(bulk_patch("", r#"{"price":1}"#), guard);
+        assert_eq!(bulk_patch("?confirm=false", r#"{"price":1}"#), guard);
+        assert_eq!(bulk_patch("?confirm=true", "{}"), no_fields);
+        assert_eq!(bulk_patch("?brand=a", "{}"), no_fields);
+        assert_eq!(bulk_patch("?year=new", r#"{"price":1}"#).1, "Invalid year filter");
+        assert_eq!(bulk_patch("?brand=a", r#"{"id":1}"#).1, "Invalid field id");
+    }
+
+    #[test]
+    fn bulk_patch_rejects_unknown_filters() {
+        assert_eq!(
+            bulk_patch("?brand=Toyota&price=1000", r#"{"price":1}"#),
+            (BAD_REQUEST.to_string(), "Invalid filter price".to_string())
+        );
+        assert_eq!(bulk_patch("?confirm=true&colour=red", r#"{"price":1}"#).1, "Invalid filter colour");
+        assert_eq!(bulk_patch("?brand=a&id", r#"{"price":1}"#).1, "Invalid filter id");
+    }
+
+    #[test]
+    #[ignore]
+    fn bulk_patch_updates_only_filtered_cars() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        insert_test_car(&mut client, "test-bulk", 2001, 100.0);
+        insert_test_car(&mut client, "test-bulk", 2001, 200.0);
+        insert_test_car(&mut client, "test-bulk", 2002, 300.0);
+
+        let response = bulk_patch("?brand=test-bulk&year=2001", r#"{"price":999.5}"#);
+        let prices: Vec<f64> = client
+            .query("SELECT price FROM cars WHERE brand = 'test-bulk' ORDER BY year, price", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        delete_test_cars(&mut client, "test-bulk");
+
+        assert_eq!(response, (OK_RESPONSE.to_string(), r#"{"updated":2}"#.to_string()));
+        assert_eq!(prices, [999.5, 999.5, 300.0]);
+    }
+
+    //run sql against constrained temp tables and map the error it fails with
+    fn constraint_error_status(sql: &str) -> String {
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        client.batch_execute(
+            "CREATE TEMP TABLE makers (name TEXT PRIMARY KEY);
+            CREATE TEMP TABLE scratch (
+                id INT PRIMARY KEY,
+                maker TEXT NOT NULL REFERENCES makers (name),
+                price INT CHECK (price > 0)
+            );
+            INSERT INTO makers VALUES ('a');
+            INSERT INTO scratch VALUES (1, 'a', 10);"
+        ).unwrap();
+
+        db_error_response(&client.batch_execute(sql).unwrap_err()).0
+    }
+
+    #[test]
+    #[ignore]
+    fn constraint_violations_map_to_status() {
+        assert_eq!(constraint_error_status("INSERT INTO scratch VALUES (1, 'a', 10)"), CONFLICT);
+        assert_eq!(constraint_error_status("INSERT INTO scratch VALUES (2, 'b', 10)"), CONFLICT);
+        assert_eq!(constraint_error_status("INSERT INTO scratch VALUES (2, 'a', -1)"), UNPROCESSABLE_ENTITY);
+        assert_eq!(constraint_error_status("INSERT INTO scratch VALUES (2, NULL, 10)"), UNPROCESSABLE_ENTITY);
+        assert_eq!(constraint_error_status("INSERT INTO scratch VALUES ("), INTERNAL_ERROR);
+    }
+
+    #[test]
+    fn selftest_is_hidden_unless_enabled() {
+        let _env = lock_env();
+        env::remove_var("SELFTEST_ENABLED");
+
+        assert_eq!(handle_selftest_request("GET /admin/selftest HTTP/1.1\r\n\r\n").0, NOT_FOUND);
+    }
+
+    #[test]
+    fn selftest_is_allowed_only_when_enabled() {
+        let _env = lock_env();
+        env::remove_var("SELFTEST_ENABLED");
+        let disabled = allowed_methods("/admin/selftest");
+        env::set_var("SELFTEST_ENABLED", "true");
+        let enabled = allowed_methods("/admin/selftest");
+        env::remove_var("SELFTEST_ENABLED");
+
+        assert_eq!(disabled, None);
+        assert_eq!(enabled.as_deref(), Some("GET, OPTIONS"));
+    }
+
+    #[test]
+    #[ignore]
+    fn selftest_passes_every_step_and_leaves_no_rows() {
+        let _env = lock_env();
+        set_database().unwrap();
+        env::set_var("SELFTEST_ENABLED", "true");
+        let (status_line, body) = handle_selftest_request("GET /admin/selftest HTTP/1.1\r\n\r\n");
+        env::remove_var("SELFTEST_ENABLED");
+
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let steps: Vec<_> = report["steps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|step| (step["step"].as_str().unwrap(), step["passed"].as_bool().unwrap()))
+            .collect();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+
+        assert_eq!(status_line, OK_RESPONSE);
+        assert_eq!(report["passed"], true);
+        assert_eq!(steps, [("create", true), ("read", true), ("update", true), ("delete", true)]);
+        assert_eq!(count_test_cars(&mut client, "selftest"), 0);
+    }
+
+    fn allowed_methods(path: &str) -> Option<String> {
+        let (status_line, _) = handle_options_request(&format!("OPTIONS {} HTTP/1.1\r\n\r\n", path));
+        get_header(&status_line, "Allow").map(str::to_string)
+    }
+
+    #[test]
+    fn every_matched_route_has_a_handler() {
+        let requests = [
+            "OPTIONS /cars", "POST /cars", "GET /cars/1/exists", "GET /cars/facets", "GET /cars/price-distribution",
+            "GET /cars/top", "GET /cars/1", "GET /cars", "GET /admin/selftest", "PATCH /cars", "PUT /cars/1",
+            "DELETE /cars/1",
+        ];
+
+        for request in requests.map(|request| format!("{} HTTP/1.1\r\n\r\n", request)) {
+            assert!(match_route(&request).is_some(), "{}", request);
+            assert_eq!(get_route(&request).map(|(route, _)| route), match_route(&request), "{}", request);
+        }
+    }
+
+    #[test]
+    fn disabled_routes_are_matched_exactly() {
+        let _env = lock_env();
+        env::set_var("DISABLED_ROUTES", "POST /cars, DELETE /cars/{id}");
+        let disabled = ["POST /cars", "DELETE /cars/{id}", "PUT /cars/{id}", "POST /carsx"].map(is_route_disabled);
+        env::remove_var("DISABLED_ROUTES");
+
+        assert_eq!(disabled, [true, true, false, false]);
+    }
+
+    #[test]
+    fn allow_header_drops_disabled_routes() {
+        let _env = lock_env();
+        let collection = allowed_methods("/cars");
+        let item = allowed_methods("/cars/1");
+
+        env::set_var("DISABLED_ROUTES", "POST /cars,DELETE /cars/{id}");
+        let collection_disabled = allowed_methods("/cars");
+        let item_disabled = allowed_methods("/cars/1");
+
+        env::set_var("DISABLED_ROUTES", "GET /cars/top");
+        let all_disabled = handle_options_request("OPTIONS /cars/top HTTP/1.1\r\n\r\n").0;
+        env::remove_var("DISABLED_ROUTES");
+
+        assert_eq!(collection.as_deref(), Some("GET, POST, PATCH, OPTIONS"));
+        assert_eq!(item.as_deref(), Some("GET, PUT, DELETE, OPTIONS"));
+        assert_eq!(collection_disabled.as_deref(), Some("GET, PATCH, OPTIONS"));
+        assert_eq!(item_disabled.as_deref(), Some("GET, PUT, OPTIONS"));
+        assert_eq!(all_disabled, NOT_FOUND);
+    }
+
+    #[test]
+    fn uri_length_is_checked_on_partial_request_line() {
+        assert!(!is_uri_too_long(b"", 8));
+        assert!(!is_uri_too_long(b"GET ", 8));
+        assert!(!is_uri_too_long(b"GET /cars/1 HTTP/1.1\r\n", 8));
+        assert!(is_uri_too_long(b"GET /cars/1234", 8));
+        assert!(is_uri_too_long(b"GET /cars/1?x=1 HTTP/1.1\r\nHost: x\r\n\r\n", 8));
+        assert!(!is_uri_too_long(b"GET /cars HTTP/1.1\r\nReferer: /a/very/long/referer\r\n", 8));
+    }
+
+    #[test]
+    fn long_uri_is_refused_before_headers_finish() {
+        let _env = lock_env();
+        env::set_var("MAX_URI_BYTES", "16");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        //the header block is never finished, only an early check can answer
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"GET /cars?brand=a-brand-name-too-long HTTP/1.1\r\nHost: x\r\n").unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        handle_client(stream);
+        let elapsed = started.elapsed();
+        let response = client.join().unwrap();
+        env::remove_var("MAX_URI_BYTES");
+
+        assert!(response.starts_with(URI_TOO_LONG.trim_end_matches("\r\n")), "{}", response);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    fn slow_handler(_request: &str) -> (String, String) {
+        thread::sleep(Duration::from_millis(500));
+        (OK_RESPONSE.to_string(), "slow".to_string())
+    }
+
+    fn fast_handler(_request: &str) -> (String, String) {
+        (OK_RESPONSE.to_string(), "fast".to_string())
+    }
+
+    #[test]
+    fn slow_handler_times_out_with_504() {
+        let (status_line, _) = run_with_timeout(slow_handler, "", Duration::from_millis(50));
+
+        assert_eq!(status_line, GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn fast_handler_finishes_within_timeout() {
+        let response = run_with_timeout(fast_handler, "", Duration::from_millis(500));
+
+        assert_eq!(response, (OK_RESPONSE.to_string(), "fast".to_string()));
+    }
+
+    #[test]
+    fn handler_timeout_prefers_route_setting() {
+        let _env = lock_env();
+        env::set_var("ROUTE_TIMEOUTS", "GET /cars=100, POST /cars=200");
+        env::set_var("HANDLER_TIMEOUT_MS", "300");
+
+        assert_eq!(get_handler_timeout("POST /cars"), Duration::from_millis(200));
+        assert_eq!(get_handler_timeout("PUT /cars/{id}"), Duration::from_millis(300));
+
+        env::remove_var("HANDLER_TIMEOUT_MS");
+        assert_eq!(get_handler_timeout("PUT /cars/{id}"), Duration::from_millis(30000));
+
+        env::remove_var("ROUTE_TIMEOUTS");
+    }
+
+    fn slow_insert_handler(request: &str) -> (String, String) {
+        let mut client = connect().unwrap();
+        let result = with_retryable_transaction(&mut client, request, |transaction| {
+            transaction.execute(
+                "INSERT INTO cars (brand, model, year, price) VALUES ('test-timeout', 'm', 2000, 1)",
+                &[]
+            )?;
+            transaction.batch_execute("SELECT pg_sleep(2)")
+        });
+
+        match result {
+            Ok(_) => (OK_RESPONSE.to_string(), "Car created".to_string()),
+            Err(e) => db_error_response(&e),
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn timed_out_write_is_cancelled() {
+        let _env = lock_env();
+        set_database().unwrap();
+
+        let (status_line, _) = run_with_timeout(slow_insert_handler, "", Duration::from_millis(300));
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let rows: i64 = client
+            .query_one("SELECT COUNT(*) FROM cars WHERE brand = 'test-timeout'", &[])
+            .unwrap()
+            .get(0);
+        let sleeping: i64 = client
+            .query_one("SELECT COUNT(*) FROM pg_stat_activity WHERE query LIKE 'SELECT pg_sleep(2)%'", &[])
+            .unwrap()
+            .get(0);
+
+        assert_eq!(status_line, GATEWAY_TIMEOUT);
+        assert_eq!(rows, 0);
+        assert_eq!(sleeping, 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn timeout_waits_for_a_commit_under_way() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let worker = Arc::new(Mutex::new(Worker::default()));
+        let handler_worker = Arc::clone(&worker);
+
+        let handler = thread::spawn(move || {
+            WORKER.with(|worker| *worker.borrow_mut() = Some(handler_worker));
+            let mut client = connect().unwrap();
+            run_transaction(&mut client, "", &mut |transaction| {
+                transaction.execute(
+                    "INSERT INTO cars (brand, model, year, price) VALUES ('test-commit-race', 'm', 2000, 1)",
+                    &[]
+                )?;
+
+                //a deferred trigger makes the commit itself take a second
+                transaction.batch_execute(
+                    "
+                    CREATE TEMP TABLE slow_commit (id INT) ON COMMIT DROP;
+                    CREATE FUNCTION pg_temp.sleep_trigger() RETURNS trigger AS $$
+                        BEGIN PERFORM pg_sleep(1); RETURN NULL; END
+                    $$ LANGUAGE plpgsql;
+                    CREATE CONSTRAINT TRIGGER slow_commit AFTER INSERT ON slow_commit
+                        DEFERRABLE INITIALLY DEFERRED FOR EACH ROW EXECUTE FUNCTION pg_temp.sleep_trigger();
+                    INSERT INTO slow_commit VALUES (1);
+                "
+                )
+            })
+        });
+        thread::sleep(Duration::from_millis(300));
+
+        //what the timeout path does, it must not get in before the commit lands
+        let started = Instant::now();
+        worker.lock().unwrap().abandoned = true;
+        let waited = started.elapsed();
+
+        let result = handler.join().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let rows = count_test_cars(&mut client, "test-commit-race");
+        delete_test_cars(&mut client, "test-commit-race");
+
+        assert!(result.is_ok());
+        assert_eq!(rows, 1);
+        assert!(waited >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn dedup_key_covers_method_path_body_and_write_headers() {
+        let base = "PUT /cars/1 HTTP/1.1\r\nHost: a\r\n\r\n{}";
+        let key = get_dedup_key(base);
+
+        assert_eq!(key, get_dedup_key("PUT /cars/1 HTTP/1.1\r\nHost: b\r\n\r\n{}"));
+        assert_ne!(key, get_dedup_key("POST /cars/1 HTTP/1.1\r\nHost: a\r\n\r\n{}"));
+        assert_ne!(key, get_dedup_key("PUT /cars/2 HTTP/1.1\r\nHost: a\r\n\r\n{}"));
+        assert_ne!(key, get_dedup_key("PUT /cars/1 HTTP/1.1\r\nHost: a\r\n\r\n{ }"));
+        assert_ne!(key, get_dedup_key("PUT /cars/1 HTTP/1.1\r\nIf-Match: \"a\"\r\n\r\n{}"));
+        assert_ne!(key, get_dedup_key("PUT /cars/1 HTTP/1.1\r\nPrefer: durability=strict\r\n\r\n{}"));
+    }
+
+    #[test]
+    #[ignore]
+    fn identical_posts_in_window_create_one_car() {
+        let _env = lock_env();
+        set_database().unwrap();
+        env::set_var("DEDUP_WINDOW_MS", "60000");
+        let body = r#"{"brand":"test-dedup","model":"m","year":2010,"price":1000.0}"#;
+        let request = format!("POST /cars HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+
+        let first = route_request(&request);
+        let second = route_request(&request);
+        env::remove_var("DEDUP_WINDOW_MS");
+
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let created = count_test_cars(&mut client, "test-dedup");
+        delete_test_cars(&mut client, "test-dedup");
+
+        assert_eq!(first.0, OK_RESPONSE);
+        assert_eq!(first, second);
+        assert_eq!(created, 1);
+    }
+
+    fn get_scratch_indexes(schema: &str, create_indexes: Option<&str>) -> Vec<String> {
+        drop_scratch_schema(schema);
+        let mut client = connect_to_scratch_schema(schema);
+
+        match create_indexes {
+            Some(value) => env::set_var("CREATE_INDEXES", value),
+            None => env::remove_var("CREATE_INDEXES"),
+        }
+        create_schema(&mut client).unwrap();
+        env::remove_var("CREATE_INDEXES");
+
+        let indexes = client
+            .query("SELECT indexname FROM pg_indexes WHERE schemaname = $1 ORDER BY indexname", &[&schema])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        drop_scratch_schema(schema);
+        indexes
+    }
+
+    #[test]
+    #[ignore]
+    fn indexes_are_created_unless_disabled() {
+        let _env = lock_env();
+
+        assert_eq!(
+            get_scratch_indexes("test_indexes", None),
+            ["cars_brand_year_idx", "cars_pkey", "cars_price_idx", "cars_year_idx"]
+        );
+        assert_eq!(get_scratch_indexes("test_indexes", Some("true")).len(), 4);
+        assert_eq!(get_scratch_indexes("test_indexes", Some("false")), ["cars_pkey"]);
+    }
+
+    const OPTIONS_REQUEST: &[u8] = b"OPTIONS /cars HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    #[test]
+    fn declared_body_over_limit_is_too_large() {
+        let declared = |length: usize| format!("POST /cars HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+        let fits = MAX_REQUEST_BYTES - declared(MAX_REQUEST_BYTES).len();
+
+        assert!(!is_request_too_large(declared(fits).as_bytes()));
+        assert!(is_request_too_large(declared(fits + 1).as_bytes()));
+        assert!(is_request_too_large(declared(usize::MAX).as_bytes()));
+        assert!(!is_request_too_large(b"POST /cars HTTP/1.1\r\nContent-Length: 99999999"));
+        assert!(is_request_too_large(&vec![b'a'; MAX_REQUEST_BYTES + 1]));
+    }
+
+    #[test]
+    fn declared_body_over_limit_is_refused_without_reading_it() {
+        let _env = lock_env();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        //only the headers are sent, the client waits for the answer
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(format!("POST /cars HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_REQUEST_BYTES).as_bytes())
+                .unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        handle_client(stream);
+        let elapsed = started.elapsed();
+        let response = client.join().unwrap();
+
+        assert!(response.starts_with(PAYLOAD_TOO_LARGE), "{}", response);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    }
+
+    //serve one connection with handle_client, the client sends request a byte at a time
+    fn serve_byte_by_byte(request: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+
+            for byte in request {
+                stream.write_all(&[*byte]).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        handle_client(stream);
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn request_sent_byte_by_byte_is_parsed() {
+        let _env = lock_env();
+        let response = serve_byte_by_byte(OPTIONS_REQUEST);
+
+        assert!(response.starts_with("HTTP/1.1 204 NO CONTENT\r\n"), "{}", response);
+        assert!(response.contains("Allow: GET, POST, PATCH, OPTIONS"), "{}", response);
+    }
+
+    #[test]
+    fn trickling_client_is_cut_off_at_read_deadline() {
+        let _env = lock_env();
+        env::set_var("READ_TIMEOUT_MS", "300");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        //a byte every 100ms, each read alone is well within the timeout
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+
+            for byte in OPTIONS_REQUEST {
+                if stream.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        let result = read_request(&mut stream, 2048);
+        let elapsed = started.elapsed();
+        drop(stream);
+        client.join().unwrap();
+        env::remove_var("READ_TIMEOUT_MS");
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    //bucket counts add up to total and percentiles never decrease
+    fn check_distribution(distribution: &serde_json::Value, buckets: usize) {
+        let total = distribution["total"].as_i64().unwrap();
+        let counts: Vec<i64> = distribution["buckets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|bucket| bucket["count"].as_i64().unwrap())
+            .collect();
+        let prices: Vec<f64> = distribution["percentiles"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|percentile| percentile["price"].as_f64().unwrap())
+            .collect();
+
+        assert_eq!(counts.len(), buckets);
+        assert_eq!(counts.iter().sum::<i64>(), total);
+        assert_eq!(prices.len(), buckets - 1);
+        assert!(prices.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", prices);
+    }
+
+    #[test]
+    #[ignore]
+    fn price_distribution_is_consistent() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+
+        for price in [100.0, 150.0, 150.0, 400.0, 900.0, 1000.0] {
+            insert_test_car(&mut client, "test-distribution", 2010, price);
+        }
+
+        let fractions: Vec<f64> = (1..4).map(|i| f64::from(i) / 4.0).collect();
+        let by_brand = get_price_distribution(&mut client, 4, Some("test-distribution"), &fractions).unwrap();
+        let all = get_price_distribution(&mut client, 4, None, &fractions).unwrap();
+        delete_test_cars(&mut client, "test-distribution");
+
+        check_distribution(&by_brand, 4);
+        check_distribution(&all, 4);
+        assert_eq!(by_brand["total"], 6);
+        assert_eq!(by_brand["min"], 100.0);
+        assert_eq!(by_brand["max"], 1000.0);
+        //the max price lands in the last bucket, not past it
+        assert_eq!(by_brand["buckets"][3]["count"], 2);
+    }
+
+    fn get_car_json(id: i32, query: &str) -> serde_json::Value {
+        let (status_line, body) = handle_get_request(&format!("GET /cars/{}{} HTTP/1.1\r\n\r\n", id, query));
+        assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn age_is_years_since_model_year_and_never_negative() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let current_year: i32 =
+            client.query_one("SELECT EXTRACT(YEAR FROM CURRENT_DATE)::INT", &[]).unwrap().get(0);
+        let old = insert_test_car(&mut client, "test-age", current_year - 5, 1000.0);
+        let future = insert_test_car(&mut client, "test-age", 3000, 1000.0);
+
+        let old_car = get_car_json(old, "?include=age");
+        let future_car = get_car_json(future, "?include=age");
+        let without_age = get_car_json(old, "");
+        delete_test_cars(&mut client, "test-age");
+
+        assert_eq!(old_car["age"], 5);
+        assert_eq!(future_car["age"], 0);
+        assert!(without_age.get("age").is_none());
+    }
+
+    fn get_top(query: &str) -> (String, String) {
+        handle_top_request(&format!("GET /cars/top{} HTTP/1.1\r\n\r\n", query))
+    }
+
+    #[test]
+    fn top_rejects_bad_parameters() {
+        assert_eq!(get_top("?by=id").1, "by must be price or year");
+        assert_eq!(get_top("?by=price%3B%20DROP%20TABLE%20cars").1, "by must be price or year");
+        assert_eq!(get_top("?order=up").1, "order must be asc or desc");
+
+        for limit in ["0", "101", "-1", "ten", ""] {
+            assert_eq!(get_top(&format!("?limit={}", limit)), (
+                BAD_REQUEST.to_string(),
+                "limit must be between 1 and 100".to_string(),
+            ));
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn top_returns_most_expensive_and_cheapest() {
+        let _env = lock_env();
+        set_database().unwrap();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let expensive = insert_test_car(&mut client, "test-top", 2010, 1e15);
+        let cheapest = insert_test_car(&mut client, "test-top", 2010, -1e15);
+
+        let most_expensive = get_top("?limit=1");
+        let lowest = get_top("?by=price&order=asc&limit=2");
+        delete_test_cars(&mut client, "test-top");
+
+        let most_expensive: Vec<Car> = serde_json::from_str(&most_expensive.1).unwrap();
+        let lowest: Vec<Car> = serde_json::from_str(&lowest.1).unwrap();
+
+        assert_eq!(most_expensive.len(), 1);
+        assert_eq!(most_expensive[0].id, Some(expensive));
+        assert_eq!(lowest.len(), 2);
+        assert_eq!(lowest[0].id, Some(cheapest));
+        assert!(lowest[0].price <= lowest[1].price);
+    }
+
+    //fail the statement with the given SQLSTATE, like the server would on a conflict
+    fn raise_sqlstate(transaction: &mut Transaction, code: &str) -> Result<(), PostgresError> {
+        transaction.batch_execute(&format!(
+            "DO $$ BEGIN RAISE EXCEPTION 'test failure' USING ERRCODE = '{}'; END $$",
+            code
+        ))
+    }
+
+    #[test]
+    #[ignore]
+    fn serialization_failure_succeeds_on_retry() {
+        let _env = lock_env();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let mut attempts = 0;
+
+        let result = with_retryable_transaction(&mut client, "", |transaction| {
+            attempts += 1;
+
+            if attempts <= 2 {
+                raise_sqlstate(transaction, "40001")?;
+            }
+
+            Ok(attempts)
+        });
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    #[ignore]
+    fn exhausted_serialization_retries_map_to_409() {
+        let _env = lock_env();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let mut attempts = 0;
+
+        let result = with_retryable_transaction(&mut client, "", |transaction| {
+            attempts += 1;
+            raise_sqlstate(transaction, "40001")
+        });
+
+        //the first try plus the default 3 retries
+        assert_eq!(attempts, 4);
+        assert_eq!(db_error_response(&result.unwrap_err()).0, CONFLICT);
+    }
+
+    #[test]
+    #[ignore]
+    fn exhausted_deadlock_retries_map_to_503() {
+        let _env = lock_env();
+        env::set_var("TRANSACTION_RETRIES", "1");
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let mut attempts = 0;
+
+        let result = with_retryable_transaction(&mut client, "", |transaction| {
+            attempts += 1;
+            raise_sqlstate(transaction, "40P01")
+        });
+        env::remove_var("TRANSACTION_RETRIES");
+
+        assert_eq!(attempts, 2);
+        assert_eq!(db_error_response(&result.unwrap_err()).0, with_header(SERVICE_UNAVAILABLE, "Retry-After", "1"));
+    }
+
+    #[test]
+    #[ignore]
+    fn other_errors_are_not_retried() {
+        let _env = lock_env();
+        let mut client = Client::connect(DB_URL, NoTls).unwrap();
+        let mut attempts = 0;
+
+        let result = with_retryable_transaction(&mut client, "", |transaction| {
+            attempts += 1;
+            raise_sqlstate(transaction, "23505")
+        });
+
+        assert_eq!(attempts, 1);
+        assert!(result.is_err());
+    }
 }